@@ -1,34 +1,396 @@
+use std::collections::VecDeque;
 use std::ffi::{CStr, CString};
-use std::os::raw::c_char;
+use std::os::raw::{c_char, c_void};
 
 // -----------------------------------------------------------------------------
 // iOS FFI (C-compatible)
 // -----------------------------------------------------------------------------
 
-/// Detects sleep state based on input intensity.
-/// Returns a pointer to a C-string "SLEEPING" or "AWAKE".
-/// Caller must NOT free this string (it's static for now).
+/// The intensity below which a sample is considered "asleep".
+const SLEEP_THRESHOLD: f32 = 0.2;
+
+/// Derives a [0, 1] confidence from how far `avg` sits on the far side of the
+/// threshold: a sample right on the threshold is 0.0, one at the extreme of
+/// its range is 1.0.
+fn confidence_for(avg: f32) -> f32 {
+    let raw = if avg < SLEEP_THRESHOLD {
+        (SLEEP_THRESHOLD - avg) / SLEEP_THRESHOLD
+    } else {
+        (avg - SLEEP_THRESHOLD) / (1.0 - SLEEP_THRESHOLD)
+    };
+    raw.clamp(0.0, 1.0)
+}
+
+/// Serializes a detector verdict as the JSON contract consumed by the apps,
+/// e.g. `{"state":"SLEEPING","confidence":0.30,"avg_intensity":0.1400}`.
+fn result_json(state: &str, confidence: f32, avg_intensity: f32) -> String {
+    format!(
+        "{{\"state\":\"{}\",\"confidence\":{:.2},\"avg_intensity\":{:.4}}}",
+        state, confidence, avg_intensity
+    )
+}
+
+/// JSON sentinel returned when an FFI entry point is handed malformed input
+/// (a null pointer, or a non-finite intensity) instead of risking UB.
+const SENTINEL_JSON: &str = "{\"state\":\"UNKNOWN\",\"error\":\"null_input\"}";
+
+/// Detects sleep state from a single intensity reading and returns a JSON
+/// result string such as
+/// `{"state":"SLEEPING","confidence":0.30,"avg_intensity":0.1400}` (for an
+/// intensity of 0.14).
+///
+/// A non-finite intensity (`NaN`/`Inf`) yields the [`SENTINEL_JSON`] UNKNOWN
+/// result rather than a bogus verdict.
+///
+/// The string is heap-allocated and ownership is transferred to the caller:
+/// it MUST be released with [`rust_free_string`] exactly once.
 #[no_mangle]
 pub extern "C" fn rust_detect_sleep(intensity: f32) -> *const c_char {
-    let state = if intensity < 0.2 {
+    let json = if !intensity.is_finite() {
+        SENTINEL_JSON.to_string()
+    } else {
+        let state = if intensity < SLEEP_THRESHOLD {
+            "SLEEPING"
+        } else {
+            "AWAKE"
+        };
+        result_json(state, confidence_for(intensity), intensity)
+    };
+    CString::new(json).unwrap().into_raw()
+}
+
+/// Extracts the `samples` array from a JSON payload of the shape
+/// `{"samples":[0.1,0.05,...],"sample_rate_hz":50}`. `sample_rate_hz` is not
+/// needed for the windowed mean/variance aggregation and is ignored. Kept
+/// dependency-free on purpose so the crate has no non-`jni` external crates.
+fn parse_sample_batch(json: &str) -> Vec<f32> {
+    json.find("\"samples\"")
+        .and_then(|key| json[key..].find('[').map(|rel| key + rel + 1))
+        .and_then(|open| json[open..].find(']').map(|rel| &json[open..open + rel]))
+        .map(|body| {
+            body.split(',')
+                .filter_map(|tok| tok.trim().parse::<f32>().ok())
+                .collect::<Vec<f32>>()
+        })
+        .unwrap_or_default()
+}
+
+/// Classifies a whole window of samples by their mean intensity and returns
+/// the same JSON result shape as [`rust_detect_sleep`]. The confidence is
+/// scaled down by the samples' standard deviation so a noisy window reads as
+/// less certain.
+fn classify_batch(samples: &[f32]) -> String {
+    let finite: Vec<f32> = samples.iter().copied().filter(|s| s.is_finite()).collect();
+    if finite.is_empty() {
+        return SENTINEL_JSON.to_string();
+    }
+    let mean: f32 = finite.iter().sum::<f32>() / finite.len() as f32;
+    let variance: f32 =
+        finite.iter().map(|s| (s - mean).powi(2)).sum::<f32>() / finite.len() as f32;
+    let state = if mean < SLEEP_THRESHOLD {
         "SLEEPING"
     } else {
         "AWAKE"
     };
-    
-    // In a real app, you might allocate this and require the caller to free it.
-    // For simplicity, we use static strings here or leak memory if dynamic.
-    let c_str = CString::new(state).unwrap();
-    c_str.into_raw()
+    // Penalise the base confidence by the window's spread: a steady window
+    // keeps its confidence, a jittery one is discounted towards 0.
+    let confidence = (confidence_for(mean) * (1.0 - variance.sqrt())).clamp(0.0, 1.0);
+    result_json(state, confidence, mean)
 }
 
-/// Frees a string allocated by Rust (if we were allocating dynamic strings).
+/// Parses a JSON sample batch and returns the windowed verdict as a heap
+/// C-string. Release it with [`rust_free_string`].
+///
+/// # Safety
+/// `json` must be null or a valid NUL-terminated C-string.
 #[no_mangle]
-pub extern "C" fn rust_free_string(s: *mut c_char) {
-    unsafe {
-        if s.is_null() { return }
-        let _ = CString::from_raw(s);
+pub unsafe extern "C" fn rust_detect_sleep_json(json: *const c_char) -> *const c_char {
+    let out = if json.is_null() {
+        SENTINEL_JSON.to_string()
+    } else {
+        let input = CStr::from_ptr(json).to_string_lossy();
+        classify_batch(&parse_sample_batch(&input))
+    };
+    CString::new(out).unwrap().into_raw()
+}
+
+/// Classifies each of `len` samples and returns a freshly-allocated array of
+/// `len` C-strings (one JSON result per sample, as produced by
+/// [`rust_detect_sleep`]). The array length is written through `out_len`.
+///
+/// The returned array is owned by the caller and MUST be released with
+/// [`rust_free_string_array`]. Returns null (and writes `0` to `out_len`) if
+/// `samples` is null.
+///
+/// # Safety
+/// `samples` must be null or point to `len` readable `f32`s, and `out_len`
+/// must be null or a writable `usize`.
+#[no_mangle]
+pub unsafe extern "C" fn rust_detect_sleep_batch(
+    samples: *const f32,
+    len: usize,
+    out_len: *mut usize,
+) -> *const *const c_char {
+    if samples.is_null() {
+        if !out_len.is_null() {
+            *out_len = 0;
+        }
+        return std::ptr::null();
+    }
+
+    let slice = std::slice::from_raw_parts(samples, len);
+    let out: Vec<*const c_char> = slice
+        .iter()
+        .map(|&intensity| {
+            let json = if !intensity.is_finite() {
+                SENTINEL_JSON.to_string()
+            } else {
+                let state = if intensity < SLEEP_THRESHOLD {
+                    "SLEEPING"
+                } else {
+                    "AWAKE"
+                };
+                result_json(state, confidence_for(intensity), intensity)
+            };
+            CString::new(json).unwrap().into_raw() as *const c_char
+        })
+        .collect();
+
+    // Hand out a boxed slice so the allocation's length is exactly `len`; this
+    // is the layout `rust_free_string_array` reconstructs, avoiding any
+    // `capacity != len` mismatch.
+    if !out_len.is_null() {
+        *out_len = out.len();
+    }
+    let boxed: Box<[*const c_char]> = out.into_boxed_slice();
+    Box::into_raw(boxed) as *const *const c_char
+}
+
+/// Frees an array returned by [`rust_detect_sleep_batch`]: drops each
+/// `CString` then the outer boxed slice, reconstructed with `Box::from_raw`
+/// from the pointer and `len`.
+///
+/// # Safety
+/// `ptr`/`len` must be exactly what a prior [`rust_detect_sleep_batch`] call
+/// returned, freed at most once.
+#[no_mangle]
+pub unsafe extern "C" fn rust_free_string_array(ptr: *const *const c_char, len: usize) {
+    if ptr.is_null() {
+        return;
+    }
+    let slice_ptr = std::ptr::slice_from_raw_parts_mut(ptr as *mut *const c_char, len);
+    let boxed = Box::from_raw(slice_ptr);
+    for &s in boxed.iter() {
+        if !s.is_null() {
+            let _ = CString::from_raw(s as *mut c_char);
+        }
+    }
+}
+
+/// Frees a string handed out by the C-ABI classifiers (e.g.
+/// [`rust_detect_sleep`], [`rust_detect_sleep_json`], [`rust_detector_feed`]).
+/// This is the mandatory, correctly-paired deallocator for those strings;
+/// calling it exactly once reclaims the `CString` allocated with `into_raw`.
+///
+/// # Safety
+/// `s` must be a pointer handed out by one of those functions, freed at most
+/// once. Null is accepted and ignored.
+#[no_mangle]
+pub unsafe extern "C" fn rust_free_string(s: *mut c_char) {
+    if s.is_null() { return }
+    let _ = CString::from_raw(s);
+}
+
+// -----------------------------------------------------------------------------
+// Stateful detector engine
+// -----------------------------------------------------------------------------
+
+/// The binary verdict the detector holds between samples.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum State {
+    Awake,
+    Sleeping,
+}
+
+impl State {
+    fn as_str(self) -> &'static str {
+        match self {
+            State::Awake => "AWAKE",
+            State::Sleeping => "SLEEPING",
+        }
+    }
+}
+
+/// A persistent sleep detector that smooths a stream of intensity samples and
+/// applies Schmitt-trigger hysteresis so transient spikes don't flip the
+/// verdict. Created with [`rust_detector_new`], fed with
+/// [`rust_detector_feed`], and destroyed with [`rust_detector_free`].
+pub struct Detector {
+    low_thresh: f32,
+    high_thresh: f32,
+    window_len: usize,
+    min_dwell_ms: i64,
+    samples: VecDeque<f32>,
+    state: State,
+    /// First timestamp at which the moving average sat on the far side of the
+    /// opposing threshold; `None` while the average is back inside the band.
+    pending_since: Option<i64>,
+    /// Fired synchronously inside [`Detector::feed`] on every AWAKE<->SLEEPING
+    /// edge. See [`rust_detector_set_transition_callback`].
+    on_transition: Option<TransitionCallback>,
+    user_data: *mut c_void,
+}
+
+/// C function invoked on a state edge, receiving the new state as a C-string
+/// (valid only for the duration of the call) and the registered `user_data`.
+type TransitionCallback = extern "C" fn(new_state: *const c_char, user_data: *mut c_void);
+
+impl Detector {
+    fn new(low_thresh: f32, high_thresh: f32, window_len: usize, min_dwell_ms: i64) -> Detector {
+        let window_len = window_len.max(1);
+        Detector {
+            low_thresh,
+            high_thresh,
+            window_len,
+            min_dwell_ms,
+            samples: VecDeque::with_capacity(window_len),
+            state: State::Awake,
+            pending_since: None,
+            on_transition: None,
+            user_data: std::ptr::null_mut(),
+        }
+    }
+
+    /// Notifies the registered callback (if any) that the verdict just flipped.
+    fn notify_transition(&self, new_state: State) {
+        if let Some(callback) = self.on_transition {
+            let state = CString::new(new_state.as_str()).unwrap();
+            callback(state.as_ptr(), self.user_data);
+        }
+    }
+
+    fn average(&self) -> f32 {
+        if self.samples.is_empty() {
+            return 0.0;
+        }
+        let sum: f32 = self.samples.iter().sum();
+        sum / self.samples.len() as f32
+    }
+
+    /// Pushes one sample, advances the hysteresis state machine and returns the
+    /// current state.
+    fn feed(&mut self, intensity: f32, timestamp_ms: i64) -> State {
+        if self.samples.len() == self.window_len {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(intensity);
+
+        let avg = self.average();
+        match self.state {
+            // AWAKE -> SLEEPING once the average stays below `low_thresh` for
+            // at least `min_dwell_ms`.
+            State::Awake => {
+                if avg < self.low_thresh {
+                    let since = *self.pending_since.get_or_insert(timestamp_ms);
+                    if timestamp_ms - since >= self.min_dwell_ms {
+                        self.state = State::Sleeping;
+                        self.pending_since = None;
+                        self.notify_transition(self.state);
+                    }
+                } else {
+                    self.pending_since = None;
+                }
+            }
+            // SLEEPING -> AWAKE once the average stays above `high_thresh` for
+            // at least `min_dwell_ms`.
+            State::Sleeping => {
+                if avg > self.high_thresh {
+                    let since = *self.pending_since.get_or_insert(timestamp_ms);
+                    if timestamp_ms - since >= self.min_dwell_ms {
+                        self.state = State::Awake;
+                        self.pending_since = None;
+                        self.notify_transition(self.state);
+                    }
+                } else {
+                    self.pending_since = None;
+                }
+            }
+        }
+        self.state
+    }
+}
+
+/// Creates a detector and hands back an opaque owning handle. Free it with
+/// [`rust_detector_free`] exactly once.
+#[no_mangle]
+pub extern "C" fn rust_detector_new(
+    low_thresh: f32,
+    high_thresh: f32,
+    window_len: usize,
+    min_dwell_ms: i64,
+) -> *mut Detector {
+    let detector = Detector::new(low_thresh, high_thresh, window_len, min_dwell_ms);
+    Box::into_raw(Box::new(detector))
+}
+
+/// Feeds one sample into a detector and returns its current state as a
+/// heap C-string ("AWAKE"/"SLEEPING"), or "UNKNOWN" if the handle is null.
+/// The returned string must be released with [`rust_free_string`].
+///
+/// # Safety
+/// `detector` must be null or a valid handle from [`rust_detector_new`].
+#[no_mangle]
+pub unsafe extern "C" fn rust_detector_feed(
+    detector: *mut Detector,
+    intensity: f32,
+    timestamp_ms: i64,
+) -> *const c_char {
+    let state = if detector.is_null() {
+        "UNKNOWN"
+    } else {
+        let detector = &mut *detector;
+        detector.feed(intensity, timestamp_ms).as_str()
+    };
+    CString::new(state).unwrap().into_raw()
+}
+
+/// Registers a callback that fires on every AWAKE<->SLEEPING edge produced by
+/// the detector. The callback is invoked **synchronously on the caller's
+/// thread inside [`rust_detector_feed`]**, receiving the new state as a
+/// C-string (valid only for the duration of the call) and the opaque
+/// `user_data` pointer — whose lifetime is the caller's responsibility and
+/// which must outlive the detector. Passing a null handle is a no-op.
+///
+/// The intended use is a Java/Swift trampoline that acquires a partial wake
+/// lock on SLEEPING and releases it on AWAKE.
+///
+/// # Safety
+/// `detector` must be null or a valid handle from [`rust_detector_new`].
+#[no_mangle]
+pub unsafe extern "C" fn rust_detector_set_transition_callback(
+    detector: *mut Detector,
+    callback: TransitionCallback,
+    user_data: *mut c_void,
+) {
+    if detector.is_null() {
+        return;
+    }
+    let detector = &mut *detector;
+    detector.on_transition = Some(callback);
+    detector.user_data = user_data;
+}
+
+/// Destroys a detector created by [`rust_detector_new`].
+///
+/// # Safety
+/// `detector` must be null or a valid handle from [`rust_detector_new`] that
+/// has not already been freed.
+#[no_mangle]
+pub unsafe extern "C" fn rust_detector_free(detector: *mut Detector) {
+    if detector.is_null() {
+        return;
     }
+    let _ = Box::from_raw(detector);
 }
 
 // -----------------------------------------------------------------------------
@@ -39,7 +401,7 @@ use jni::JNIEnv;
 #[cfg(target_os = "android")]
 use jni::objects::{JClass, JString};
 #[cfg(target_os = "android")]
-use jni::sys::jstring;
+use jni::sys::{jlong, jstring};
 
 #[cfg(target_os = "android")]
 #[no_mangle]
@@ -48,12 +410,169 @@ pub extern "system" fn Java_com_example_sleepdetection_MainActivity_detectSleepI
     _class: JClass,
     intensity: f32,
 ) -> jstring {
-    let state = if intensity < 0.2 {
+    let state = if intensity < SLEEP_THRESHOLD {
         "SLEEPING"
     } else {
         "AWAKE"
     };
+    let json = result_json(state, confidence_for(intensity), intensity);
+
+    let output = env.new_string(json).expect("Couldn't create java string!");
+    output.into_raw()
+}
+
+#[cfg(target_os = "android")]
+#[no_mangle]
+pub extern "system" fn Java_com_example_sleepdetection_MainActivity_detectSleepFromJson(
+    env: JNIEnv,
+    _class: JClass,
+    json: JString,
+) -> jstring {
+    if json.is_null() {
+        let _ = env.throw_new("java/lang/IllegalArgumentException", "json must not be null");
+        return std::ptr::null_mut();
+    }
+    let input: String = match env.get_string(json) {
+        Ok(s) => s.into(),
+        Err(_) => {
+            let _ = env.throw_new(
+                "java/lang/IllegalArgumentException",
+                "could not read json string",
+            );
+            return std::ptr::null_mut();
+        }
+    };
+    let json = classify_batch(&parse_sample_batch(&input));
+
+    let output = env.new_string(json).expect("Couldn't create java string!");
+    output.into_raw()
+}
+
+#[cfg(target_os = "android")]
+#[no_mangle]
+pub extern "system" fn Java_com_example_sleepdetection_MainActivity_detectorNew(
+    _env: JNIEnv,
+    _class: JClass,
+    low_thresh: f32,
+    high_thresh: f32,
+    window_len: jlong,
+    min_dwell_ms: jlong,
+) -> jlong {
+    let detector = Detector::new(
+        low_thresh,
+        high_thresh,
+        window_len.max(0) as usize,
+        min_dwell_ms,
+    );
+    Box::into_raw(Box::new(detector)) as jlong
+}
+
+#[cfg(target_os = "android")]
+#[no_mangle]
+pub extern "system" fn Java_com_example_sleepdetection_MainActivity_detectorFeed(
+    env: JNIEnv,
+    _class: JClass,
+    handle: jlong,
+    intensity: f32,
+    timestamp_ms: jlong,
+) -> jstring {
+    let state = if handle == 0 {
+        "UNKNOWN"
+    } else {
+        let detector = unsafe { &mut *(handle as *mut Detector) };
+        detector.feed(intensity, timestamp_ms).as_str()
+    };
 
     let output = env.new_string(state).expect("Couldn't create java string!");
     output.into_raw()
 }
+
+#[cfg(target_os = "android")]
+#[no_mangle]
+pub extern "system" fn Java_com_example_sleepdetection_MainActivity_detectorFree(
+    _env: JNIEnv,
+    _class: JClass,
+    handle: jlong,
+) {
+    if handle == 0 {
+        return;
+    }
+    unsafe {
+        let _ = Box::from_raw(handle as *mut Detector);
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Tests
+// -----------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hysteresis_requires_dwell_before_sleeping() {
+        // window 3, dwell 100ms: a single low average must not flip the verdict.
+        let mut d = Detector::new(0.2, 0.4, 3, 100);
+        assert_eq!(d.feed(0.05, 0), State::Awake);
+        // Average is already below low_thresh, but the dwell has not elapsed.
+        assert_eq!(d.feed(0.05, 50), State::Awake);
+        // Dwell satisfied -> SLEEPING.
+        assert_eq!(d.feed(0.05, 150), State::Sleeping);
+    }
+
+    #[test]
+    fn transient_spike_does_not_flip_verdict() {
+        let mut d = Detector::new(0.2, 0.4, 3, 100);
+        d.feed(0.05, 0);
+        d.feed(0.05, 150);
+        assert_eq!(d.state, State::Sleeping);
+        // A lone high spike only lifts the 3-sample average to ~0.37, still
+        // below high_thresh, so the verdict stays SLEEPING.
+        assert_eq!(d.feed(1.0, 160), State::Sleeping);
+    }
+
+    #[test]
+    fn schmitt_trigger_returns_to_awake_after_dwell() {
+        let mut d = Detector::new(0.2, 0.4, 2, 100);
+        d.feed(0.05, 0);
+        d.feed(0.05, 150);
+        assert_eq!(d.state, State::Sleeping);
+        // Sustained high average above high_thresh for the dwell -> AWAKE.
+        assert_eq!(d.feed(0.9, 200), State::Sleeping);
+        assert_eq!(d.feed(0.9, 350), State::Awake);
+    }
+
+    #[test]
+    fn batch_round_trip_allocates_and_frees() {
+        let samples = [0.05f32, 0.5, f32::NAN];
+        let mut out_len: usize = 0;
+        let ptr = unsafe { rust_detect_sleep_batch(samples.as_ptr(), samples.len(), &mut out_len) };
+        assert!(!ptr.is_null());
+        assert_eq!(out_len, 3);
+
+        let entries = unsafe { std::slice::from_raw_parts(ptr, out_len) };
+        let first = unsafe { CStr::from_ptr(entries[0]) }.to_str().unwrap();
+        let third = unsafe { CStr::from_ptr(entries[2]) }.to_str().unwrap();
+        assert!(first.contains("SLEEPING"));
+        assert_eq!(third, SENTINEL_JSON);
+
+        // Must not leak or double-free.
+        unsafe { rust_free_string_array(ptr, out_len) };
+    }
+
+    #[test]
+    fn detect_sleep_guards_non_finite() {
+        let ptr = rust_detect_sleep(f32::NAN);
+        let json = unsafe { CStr::from_ptr(ptr) }.to_str().unwrap().to_owned();
+        assert_eq!(json, SENTINEL_JSON);
+        unsafe { rust_free_string(ptr as *mut c_char) };
+    }
+
+    #[test]
+    fn null_inputs_yield_sentinel() {
+        let ptr = unsafe { rust_detect_sleep_json(std::ptr::null()) };
+        let json = unsafe { CStr::from_ptr(ptr) }.to_str().unwrap().to_owned();
+        assert_eq!(json, SENTINEL_JSON);
+        unsafe { rust_free_string(ptr as *mut c_char) };
+    }
+}